@@ -12,20 +12,34 @@ use blockchain::utils::{self, le};
 /// Also known as CompactSize
 #[derive(Debug, Clone)]
 pub struct VarUint {
-    pub value: u64,     // Represents bytes as uint value
-    buf: Vec<u8>        // Raw bytes used for serialization (uint8 .. uint64 possible). (little endian)
+    pub value: u64      // Represents bytes as uint value; serialized form is derived on demand
 }
 
 impl VarUint {
-    fn new(value: u64, buf: Vec<u8>) -> VarUint {
-        let v = VarUint { value: value as u64, buf: buf };
+    fn new(value: u64) -> VarUint {
+        let v = VarUint { value: value };
         if v.value > 999999 {
             warn!(target: "varuint", "Potential malformed value detected: {:10}, len: {:5}, buf: 0x{}",
-                  v.value, &v.to_bytes().len(), utils::arr_to_hex(&v.to_bytes()));
+                  v.value, &v.len(), utils::arr_to_hex(&v.to_bytes()));
         }
         return v;
     }
 
+    /// Number of bytes this value serializes to, computed purely from its magnitude.
+    pub fn len(&self) -> usize {
+        match self.value {
+            0x00...0xfc => 1,
+            0xfd...0xffff => 3,
+            0x10000...0xffffffff => 5,
+            _ => 9,
+        }
+    }
+
+    /// A `VarUint` is always at least one byte wide.
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
     pub fn read_from<R: Read + ?Sized>(reader: &mut R) -> io::Result<VarUint> {
         let first = try!(reader.read_u8()); // read first length byte
         let vint = match first {
@@ -37,44 +51,128 @@ impl VarUint {
         };
         Ok(vint)
     }
+
+    /// Same as `read_from`, but rejects non-canonical (over-long) encodings.
+    pub fn read_from_strict<R: Read + ?Sized>(reader: &mut R) -> io::Result<VarUint> {
+        let first = try!(reader.read_u8());
+        let vint = match first {
+            0x00...0xfc => VarUint::from(first),
+            0xfd => {
+                let value = try!(reader.read_u16::<LittleEndian>());
+                if (value as u64) < 0xfd {
+                    return Err(Error::new(ErrorKind::InvalidData, "Non-canonical VarUint encoding"));
+                }
+                VarUint::from(value)
+            }
+            0xfe => {
+                let value = try!(reader.read_u32::<LittleEndian>());
+                if (value as u64) < 0x10000 {
+                    return Err(Error::new(ErrorKind::InvalidData, "Non-canonical VarUint encoding"));
+                }
+                VarUint::from(value)
+            }
+            0xff => {
+                let value = try!(reader.read_u64::<LittleEndian>());
+                if value < 0x100000000 {
+                    return Err(Error::new(ErrorKind::InvalidData, "Non-canonical VarUint encoding"));
+                }
+                VarUint::from(value)
+            }
+            _ => return Err(Error::new(ErrorKind::InvalidData, "Invalid VarUint value")),
+        };
+        Ok(vint)
+    }
+
+    /// Encodes `value` as a base-128 LEB128 varint (7 value bits per byte, little
+    /// endian, continuation bit set on all but the last byte).
+    pub fn to_leb128(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let mut value = self.value;
+        loop {
+            let mut byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value != 0 {
+                byte |= 0x80;
+            }
+            buf.push(byte);
+            if value == 0 {
+                break;
+            }
+        }
+        buf
+    }
+
+    /// Decodes a base-128 LEB128 varint written by `to_leb128`.
+    pub fn read_leb128<R: Read + ?Sized>(reader: &mut R) -> io::Result<VarUint> {
+        let mut value: u64 = 0;
+        for i in 0..10 {
+            let byte = try!(reader.read_u8());
+            // The 10th byte only has room for bit 63; any higher bit set here
+            // doesn't correspond to a valid u64 encoding.
+            if i == 9 && (byte & 0x7f) > 1 {
+                return Err(Error::new(ErrorKind::InvalidData, "LEB128 VarUint exceeds u64 range"));
+            }
+            value |= ((byte & 0x7f) as u64) << (7 * i);
+            if byte & 0x80 == 0 {
+                return Ok(VarUint::from(value));
+            }
+        }
+        Err(Error::new(ErrorKind::InvalidData, "LEB128 VarUint exceeds u64 range"))
+    }
+
+    /// Same as `read_from`, but rejects decoded values above `max`.
+    pub fn read_from_capped<R: Read + ?Sized>(reader: &mut R, max: u64) -> io::Result<VarUint> {
+        let vint = try!(VarUint::read_from(reader));
+        if vint.value > max {
+            return Err(Error::new(ErrorKind::InvalidData, "VarUint value exceeds allowed maximum"));
+        }
+        Ok(vint)
+    }
 }
 
 impl From<u8> for VarUint {
     fn from(value: u8) -> Self {
-        VarUint::new(value as u64, vec![value])
+        VarUint::new(value as u64)
     }
 }
 
 impl From<u16> for VarUint {
     fn from(value: u16) -> Self {
-        let mut buf: Vec<u8> = Vec::with_capacity(3);
-        buf.push(0xfd);
-        buf.extend_from_slice(&le::u16_to_array(value));
-        VarUint::new(value as u64, buf)
+        VarUint::new(value as u64)
     }
 }
 
 impl From<u32> for VarUint {
     fn from(value: u32) -> Self {
-        let mut buf: Vec<u8> = Vec::with_capacity(5);
-        buf.push(0xfe);
-        buf.extend_from_slice(&le::u32_to_array(value));
-        VarUint::new(value as u64, buf)
+        VarUint::new(value as u64)
     }
 }
 
 impl From<u64> for VarUint {
     fn from(value: u64) -> Self {
-        let mut buf: Vec<u8> = Vec::with_capacity(9);
-        buf.push(0xff);
-        buf.extend_from_slice(&le::u64_to_array(value));
-        VarUint::new(value as u64, buf)
+        VarUint::new(value)
     }
 }
 
 impl ToRaw for VarUint {
     fn to_bytes(&self) -> Vec<u8> {
-        self.buf.clone()
+        let mut buf: Vec<u8> = Vec::with_capacity(self.len());
+        match self.value {
+            0x00...0xfc => buf.push(self.value as u8),
+            0xfd...0xffff => {
+                buf.push(0xfd);
+                buf.extend_from_slice(&le::u16_to_array(self.value as u16));
+            }
+            0x10000...0xffffffff => {
+                buf.push(0xfe);
+                buf.extend_from_slice(&le::u32_to_array(self.value as u32));
+            }
+            _ => {
+                buf.push(0xff);
+                buf.extend_from_slice(&le::u64_to_array(self.value));
+            }
+        }
+        buf
     }
 }
 
@@ -84,11 +182,50 @@ impl Display for VarUint {
     }
 }
 
+/// Signed variable length integer, zigzag-encoded onto a `VarUint`
+#[derive(Debug, Clone)]
+pub struct VarZigZag {
+    pub value: i64
+}
+
+impl VarZigZag {
+    fn zigzag_encode(value: i64) -> u64 {
+        ((value << 1) ^ (value >> 63)) as u64
+    }
+
+    fn zigzag_decode(value: u64) -> i64 {
+        (value >> 1) as i64 ^ -((value & 1) as i64)
+    }
+
+    pub fn read_from<R: Read + ?Sized>(reader: &mut R) -> io::Result<VarZigZag> {
+        let vint = try!(VarUint::read_from(reader));
+        Ok(VarZigZag::from(VarZigZag::zigzag_decode(vint.value)))
+    }
+}
+
+impl From<i64> for VarZigZag {
+    fn from(value: i64) -> Self {
+        VarZigZag { value: value }
+    }
+}
+
+impl ToRaw for VarZigZag {
+    fn to_bytes(&self) -> Vec<u8> {
+        VarUint::from(VarZigZag::zigzag_encode(self.value)).to_bytes()
+    }
+}
+
+impl Display for VarZigZag {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "{}", self.value)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::io;
     use blockchain::proto::ToRaw;
-    use blockchain::proto::varuint::VarUint;
+    use blockchain::proto::varuint::{VarUint, VarZigZag};
 
     #[test]
     fn test_varuint_u8() {
@@ -144,4 +281,117 @@ mod tests {
         let test = VarUint::read_from(&mut cursor);
         assert_eq!(vec![0xfe, 0x55, 0xa1, 0xae, 0xc6], test.unwrap().to_bytes());
     }
+
+    #[test]
+    fn test_varuint_read_from_strict_accepts_canonical() {
+        let mut cursor = io::Cursor::new([0xfd, 0xfd, 0x00]);
+        let test = VarUint::read_from_strict(&mut cursor);
+        assert_eq!(0xfd, test.unwrap().value);
+
+        let mut cursor = io::Cursor::new([0xfe, 0x00, 0x00, 0x01, 0x00]);
+        let test = VarUint::read_from_strict(&mut cursor);
+        assert_eq!(0x10000, test.unwrap().value);
+
+        let mut cursor = io::Cursor::new([0xff, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00]);
+        let test = VarUint::read_from_strict(&mut cursor);
+        assert_eq!(0x100000000, test.unwrap().value);
+    }
+
+    #[test]
+    fn test_varuint_read_from_capped_accepts_within_bound() {
+        let mut cursor = io::Cursor::new([0xfe, 0x55, 0xa1, 0xae, 0xc6]);
+        let test = VarUint::read_from_capped(&mut cursor, 0xffffffff);
+        assert_eq!(3333333333, test.unwrap().value);
+    }
+
+    #[test]
+    fn test_varuint_read_from_capped_rejects_over_bound() {
+        let mut cursor = io::Cursor::new([0xff, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01]);
+        assert!(VarUint::read_from_capped(&mut cursor, 1_000_000).is_err());
+    }
+
+    #[test]
+    fn test_varuint_len() {
+        assert_eq!(1, VarUint::from(0xfcu8).len());
+        assert_eq!(3, VarUint::from(0xfdu16).len());
+        assert_eq!(3, VarUint::from(0xffffu16).len());
+        assert_eq!(5, VarUint::from(0x10000u32).len());
+        assert_eq!(5, VarUint::from(0xffffffffu32).len());
+        assert_eq!(9, VarUint::from(0x100000000u64).len());
+    }
+
+    #[test]
+    fn test_varuint_is_empty() {
+        assert!(!VarUint::from(0u8).is_empty());
+    }
+
+    #[test]
+    fn test_varuint_read_from_strict_rejects_overlong() {
+        // 5 encoded as a 9-byte 0xff form instead of a single byte
+        let mut cursor = io::Cursor::new([0xff, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        assert!(VarUint::read_from_strict(&mut cursor).is_err());
+
+        // 0xfc encoded via the 0xfd marker instead of a single byte
+        let mut cursor = io::Cursor::new([0xfd, 0xfc, 0x00]);
+        assert!(VarUint::read_from_strict(&mut cursor).is_err());
+
+        // 0xffff encoded via the 0xfe marker instead of 0xfd
+        let mut cursor = io::Cursor::new([0xfe, 0xff, 0xff, 0x00, 0x00]);
+        assert!(VarUint::read_from_strict(&mut cursor).is_err());
+    }
+
+    fn leb128_roundtrip(v: u64) {
+        let test = VarUint::from(v);
+        let mut cursor = io::Cursor::new(test.to_leb128());
+        assert_eq!(v, VarUint::read_leb128(&mut cursor).unwrap().value);
+    }
+
+    #[test]
+    fn test_varuint_leb128_roundtrip() {
+        leb128_roundtrip(0);
+        leb128_roundtrip(1);
+        leb128_roundtrip(127);
+        leb128_roundtrip(128);
+        leb128_roundtrip(300);
+        leb128_roundtrip(u64::max_value());
+    }
+
+    #[test]
+    fn test_varuint_leb128_encoding() {
+        assert_eq!(vec![0x00], VarUint::from(0u64).to_leb128());
+        assert_eq!(vec![0x7f], VarUint::from(127u64).to_leb128());
+        assert_eq!(vec![0x80, 0x01], VarUint::from(128u64).to_leb128());
+        assert_eq!(vec![0xac, 0x02], VarUint::from(300u64).to_leb128());
+    }
+
+    #[test]
+    fn test_varuint_leb128_rejects_overlong() {
+        let mut cursor = io::Cursor::new([0xff; 11]);
+        assert!(VarUint::read_leb128(&mut cursor).is_err());
+    }
+
+    #[test]
+    fn test_varuint_leb128_rejects_non_canonical_last_byte() {
+        // Canonical u64::MAX ends in 0x01; 0x7f in the last group carries
+        // garbage bits that don't fit in a u64.
+        let mut cursor = io::Cursor::new([0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f]);
+        assert!(VarUint::read_leb128(&mut cursor).is_err());
+
+        let mut cursor = io::Cursor::new([0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]);
+        assert_eq!(u64::max_value(), VarUint::read_leb128(&mut cursor).unwrap().value);
+    }
+
+    fn zigzag_roundtrip(v: i64) {
+        let test = VarZigZag::from(v);
+        let mut cursor = io::Cursor::new(test.to_bytes());
+        assert_eq!(v, VarZigZag::read_from(&mut cursor).unwrap().value);
+    }
+
+    #[test]
+    fn test_varzigzag_roundtrip() {
+        zigzag_roundtrip(0);
+        zigzag_roundtrip(-1);
+        zigzag_roundtrip(i64::min_value());
+        zigzag_roundtrip(i64::max_value());
+    }
 }